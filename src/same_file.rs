@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::path::Path;
 
@@ -14,6 +16,11 @@ use std::path::Path;
 // dev/inode on Unix and check `nFileIndex{High,Low}` on Windows.) So this may
 // be a candidate for extracting into a separate crate.
 //
+// The real work happens in one of three platform-specific modules below:
+// `unix`, `windows`, or `unknown` (the conservative canonicalize-and-compare
+// fallback used for everything else, so the crate still builds on targets we
+// don't have a real identity check for).
+//
 // ---AG
 
 /// Returns true if the two file paths may correspond to the same file.
@@ -36,56 +43,319 @@ pub fn is_same_file<P, Q>(
     path1: P,
     path2: Q,
 ) -> io::Result<bool> where P: AsRef<Path>, Q: AsRef<Path> {
-    impl_is_same_file(path1, path2)
+    Ok(try!(Handle::from_path(path1)) == try!(Handle::from_path(path2)))
+}
+
+/// A handle to an open file that can be cheaply compared for equality with
+/// other handles.
+///
+/// `Handle` exists so that callers comparing one reference path against many
+/// candidates (e.g., walkdir's ancestor loop detection, which checks every
+/// directory on the current path stack against the directory it's about to
+/// descend into) don't have to pay the cost of opening and stat-ing the
+/// reference path again on every comparison. Build one `Handle` up front and
+/// compare candidates against it with `==` instead of calling
+/// `is_same_file` in a loop. `Handle` also implements `Hash` consistently
+/// with `Eq`, so a set of handles (e.g. the directories on the current
+/// ancestor path during a walk) can be kept in a `HashSet<Handle>` and
+/// tested for membership in amortized O(1) instead of doing an O(depth)
+/// linear scan of pairwise comparisons — see `AncestorHandles`, which
+/// packages exactly that for a `follow_links` walker's loop detection.
+///
+/// On Unix and Windows, the underlying file stays open for the lifetime of
+/// the `Handle` (the fallback used on other targets only keeps the
+/// canonicalized path and holds no file open). On Windows, this is required for correctness: the file index numbers used to
+/// identify a file are not guaranteed to remain stable once every handle
+/// referencing that file is closed.
+pub struct Handle(HandleImpl);
+
+impl Handle {
+    /// Construct a handle from a path.
+    ///
+    /// This opens and stats the given path, so it carries the same cost
+    /// (and the same false-positive caveats documented on `is_same_file`)
+    /// as a single call to `is_same_file`. The benefit comes from reusing
+    /// the resulting `Handle` across many subsequent comparisons.
+    pub fn from_path<P: AsRef<Path>>(p: P) -> io::Result<Handle> {
+        HandleImpl::from_path(p.as_ref()).map(Handle)
+    }
+
+    /// Construct a handle for the process's standard input stream.
+    ///
+    /// This can be compared against a `Handle::from_path` of a candidate
+    /// output path to check whether writing to that path would clobber a
+    /// stream the process already has open, e.g. a redirected stdout.
+    pub fn stdin() -> io::Result<Handle> {
+        HandleImpl::stdin().map(Handle)
+    }
+
+    /// Construct a handle for the process's standard output stream.
+    pub fn stdout() -> io::Result<Handle> {
+        HandleImpl::stdout().map(Handle)
+    }
+
+    /// Construct a handle for the process's standard error stream.
+    pub fn stderr() -> io::Result<Handle> {
+        HandleImpl::stderr().map(Handle)
+    }
+}
+
+impl PartialEq for Handle {
+    fn eq(&self, other: &Handle) -> bool {
+        self.0.key() == other.0.key()
+    }
+}
+
+impl Eq for Handle {}
+
+impl Hash for Handle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.key().hash(state)
+    }
+}
+
+/// The set of directory handles a `follow_links`-enabled walker has
+/// currently descended into, used to detect symlink loops in amortized
+/// O(1) instead of comparing a candidate directory against every ancestor
+/// on the current path in turn.
+///
+/// A walker pushes a `Handle` for each directory as it descends, checks
+/// whether a candidate directory `contains` one already on the stack
+/// before following a symlink into it, and `pop`s the handle back off when
+/// it backs out of that directory.
+#[derive(Default)]
+pub struct AncestorHandles(HashSet<Handle>);
+
+impl AncestorHandles {
+    /// Create an empty set of ancestor handles.
+    pub fn new() -> AncestorHandles {
+        AncestorHandles(HashSet::new())
+    }
+
+    /// Returns true if `candidate` is already on the current path, i.e.
+    /// following a symlink into it would create a loop.
+    pub fn contains(&self, candidate: &Handle) -> bool {
+        self.0.contains(candidate)
+    }
+
+    /// Record that the walk has descended into `handle`.
+    pub fn push(&mut self, handle: Handle) {
+        self.0.insert(handle);
+    }
+
+    /// Record that the walk has backed out of `handle`.
+    pub fn pop(&mut self, handle: &Handle) {
+        self.0.remove(handle);
+    }
 }
 
 #[cfg(unix)]
-fn impl_is_same_file<P, Q>(
-    p1: P,
-    p2: Q,
-) -> io::Result<bool>
-where P: AsRef<Path>, Q: AsRef<Path> {
-    use std::fs;
+use self::unix::Handle as HandleImpl;
+#[cfg(windows)]
+use self::windows::Handle as HandleImpl;
+#[cfg(not(any(unix, windows)))]
+use self::unknown::Handle as HandleImpl;
+
+#[cfg(unix)]
+mod unix {
+    use std::fs::File;
+    use std::io;
     use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+    use std::path::Path;
+
+    #[derive(Debug)]
+    pub struct Handle {
+        // Kept open for the lifetime of the handle so that the identity
+        // key below remains meaningful to compare, mirroring what the
+        // Windows implementation requires.
+        file: RawFile,
+        dev: u64,
+        ino: u64,
+    }
+
+    impl Handle {
+        pub fn from_path(p: &Path) -> io::Result<Handle> {
+            Handle::from_file(try!(File::open(p)), false)
+        }
+
+        pub fn stdin() -> io::Result<Handle> {
+            Handle::from_std_fd(0)
+        }
 
-    let md1 = try!(fs::metadata(p1));
-    let md2 = try!(fs::metadata(p2));
-    Ok((md1.dev(), md1.ino()) == (md2.dev(), md2.ino()))
+        pub fn stdout() -> io::Result<Handle> {
+            Handle::from_std_fd(1)
+        }
+
+        pub fn stderr() -> io::Result<Handle> {
+            Handle::from_std_fd(2)
+        }
+
+        fn from_std_fd(fd: RawFd) -> io::Result<Handle> {
+            // Safe because we never let the resulting `File` close this
+            // fd: see `RawFile`'s `Drop` impl below.
+            Handle::from_file(unsafe { File::from_raw_fd(fd) }, true)
+        }
+
+        fn from_file(file: File, is_std: bool) -> io::Result<Handle> {
+            // `RawFile` owns `file` from this point on, so if `metadata`
+            // below fails and we bail out via `try!`, dropping it still
+            // honors `is_std` instead of closing a standard stream fd.
+            let file = RawFile::new(file, is_std);
+            let md = try!(file.metadata());
+            Ok(Handle { file: file, dev: md.dev(), ino: md.ino() })
+        }
+
+        pub fn key(&self) -> (u64, u64) {
+            (self.dev, self.ino)
+        }
+    }
+
+    // Wraps a `File` that may back one of the process's standard streams,
+    // so that the "never close a std fd we don't own" rule is enforced by
+    // a single `Drop` impl regardless of which code path drops it (success
+    // or an error before `Handle` is ever constructed).
+    #[derive(Debug)]
+    struct RawFile {
+        file: Option<File>,
+        is_std: bool,
+    }
+
+    impl RawFile {
+        fn new(file: File, is_std: bool) -> RawFile {
+            RawFile { file: Some(file), is_std: is_std }
+        }
+
+        fn metadata(&self) -> io::Result<::std::fs::Metadata> {
+            self.file.as_ref().unwrap().metadata()
+        }
+    }
+
+    impl Drop for RawFile {
+        fn drop(&mut self) {
+            if self.is_std {
+                // Release the fd without closing it; the process still
+                // owns its standard streams.
+                if let Some(file) = self.file.take() {
+                    let _ = file.into_raw_fd();
+                }
+            }
+        }
+    }
 }
 
 #[cfg(windows)]
-fn impl_is_same_file<P, Q>(
-    p1: P,
-    p2: Q,
-) -> io::Result<bool>
-where P: AsRef<Path>, Q: AsRef<Path> {
-    use std::ops::{Deref, Drop};
+mod windows {
+    use std::io;
+    use std::ops::Drop;
     use std::os::windows::prelude::*;
+    use std::path::Path;
     use std::ptr;
 
     use kernel32;
     use winapi::{self, HANDLE};
-    use winapi::fileapi::{
-        BY_HANDLE_FILE_INFORMATION,
-    };
+    use winapi::fileapi::BY_HANDLE_FILE_INFORMATION;
+
+    use self::sys::{FILE_ID_INFO, FileIdInfo};
+
+    #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+    pub enum Key {
+        /// A 128-bit file identifier read via `FILE_ID_INFO`
+        /// (`GetFileInformationByHandleEx`, Windows Server 2012+). Unlike
+        /// `nFileIndex{High,Low}`, this is documented to be unique on ReFS.
+        Id128 { volume: u64, id: u128 },
+        /// Falls back to the legacy 64-bit file index on older Windows
+        /// versions or file systems where `FILE_ID_INFO` isn't available.
+        /// The file size is folded in, mitigating (without eliminating)
+        /// the false-positive risk `nFileIndex{High,Low}` carries on ReFS.
+        Legacy {
+            volume: u32,
+            index_high: u32,
+            index_low: u32,
+            size_high: u32,
+            size_low: u32,
+        },
+    }
 
-    struct Handle(HANDLE);
+    #[derive(Debug)]
+    pub struct Handle {
+        handle: RawHandle,
+        key: Key,
+    }
 
-    impl Drop for Handle {
+    #[derive(Debug)]
+    struct RawHandle {
+        handle: HANDLE,
+        // When `true`, `handle` is one of the process's standard stream
+        // handles, which we don't own and must not close on drop.
+        is_std: bool,
+    }
+
+    impl Drop for RawHandle {
         fn drop(&mut self) {
-            unsafe { let _ = kernel32::CloseHandle(self.0); }
+            if !self.is_std {
+                unsafe { let _ = kernel32::CloseHandle(self.handle); }
+            }
         }
     }
 
-    impl Deref for Handle {
-        type Target = HANDLE;
-        fn deref(&self) -> &HANDLE { &self.0 }
+    impl Handle {
+        pub fn from_path(p: &Path) -> io::Result<Handle> {
+            Handle::from_raw(try!(open_read_attr(p)))
+        }
+
+        pub fn stdin() -> io::Result<Handle> {
+            Handle::from_std_handle(self::sys::STD_INPUT_HANDLE)
+        }
+
+        pub fn stdout() -> io::Result<Handle> {
+            Handle::from_std_handle(self::sys::STD_OUTPUT_HANDLE)
+        }
+
+        pub fn stderr() -> io::Result<Handle> {
+            Handle::from_std_handle(self::sys::STD_ERROR_HANDLE)
+        }
+
+        fn from_std_handle(which: self::sys::DWORD) -> io::Result<Handle> {
+            let h = unsafe { self::sys::GetStdHandle(which) };
+            if h.is_null() || h == winapi::INVALID_HANDLE_VALUE {
+                return Err(io::Error::last_os_error());
+            }
+            Handle::from_raw(RawHandle { handle: h, is_std: true })
+        }
+
+        fn from_raw(raw: RawHandle) -> io::Result<Handle> {
+            let key = match file_id_info(&raw) {
+                Ok(id) => Key::Id128 {
+                    volume: id.VolumeSerialNumber,
+                    id: u128::from_le_bytes(id.FileId.Identifier),
+                },
+                // Not every OS/file system combination supports
+                // `FILE_ID_INFO` (it needs Windows Server 2012+), so fall
+                // back to the older, narrower file index on failure.
+                Err(_) => {
+                    let info = try!(file_info(&raw));
+                    Key::Legacy {
+                        volume: info.dwVolumeSerialNumber,
+                        index_high: info.nFileIndexHigh,
+                        index_low: info.nFileIndexLow,
+                        size_high: info.nFileSizeHigh,
+                        size_low: info.nFileSizeLow,
+                    }
+                }
+            };
+            Ok(Handle { handle: raw, key: key })
+        }
+
+        pub fn key(&self) -> Key {
+            self.key
+        }
     }
 
-    fn file_info(h: &Handle) -> io::Result<BY_HANDLE_FILE_INFORMATION> {
+    fn file_info(h: &RawHandle) -> io::Result<BY_HANDLE_FILE_INFORMATION> {
         unsafe {
             let mut info: BY_HANDLE_FILE_INFORMATION = ::std::mem::zeroed();
-            if kernel32::GetFileInformationByHandle(**h, &mut info) == 0 {
+            if kernel32::GetFileInformationByHandle(h.handle, &mut info) == 0 {
                 Err(io::Error::last_os_error())
             } else {
                 Ok(info)
@@ -93,13 +363,70 @@ where P: AsRef<Path>, Q: AsRef<Path> {
         }
     }
 
-    fn open_read_attr<P: AsRef<Path>>(p: P) -> io::Result<Handle> {
+    fn file_id_info(h: &RawHandle) -> io::Result<FILE_ID_INFO> {
+        unsafe {
+            let mut info: FILE_ID_INFO = ::std::mem::zeroed();
+            let info_ptr = &mut info as *mut _ as self::sys::LPVOID;
+            let size = ::std::mem::size_of::<FILE_ID_INFO>() as self::sys::DWORD;
+            if self::sys::GetFileInformationByHandleEx(
+                h.handle, FileIdInfo, info_ptr, size,
+            ) == 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(info)
+            }
+        }
+    }
+
+    // `GetFileInformationByHandleEx`/`FILE_ID_INFO` and the `STD_*_HANDLE`
+    // constants were only added to the `winapi`/`kernel32-sys` 0.2 bindings
+    // this crate otherwise relies on in a later point release than the one
+    // pinned here, so rather than assume they're present, declare the
+    // handful of pieces we need ourselves.
+    #[allow(non_snake_case, non_camel_case_types, non_upper_case_globals)]
+    mod sys {
+        use winapi::HANDLE;
+
+        pub type DWORD = u32;
+        pub type BOOL = i32;
+        pub type LPVOID = *mut u8;
+
+        pub const FileIdInfo: DWORD = 18;
+
+        pub const STD_INPUT_HANDLE: DWORD = -10i32 as DWORD;
+        pub const STD_OUTPUT_HANDLE: DWORD = -11i32 as DWORD;
+        pub const STD_ERROR_HANDLE: DWORD = -12i32 as DWORD;
+
+        #[repr(C)]
+        pub struct FILE_ID_128 {
+            pub Identifier: [u8; 16],
+        }
+
+        #[repr(C)]
+        pub struct FILE_ID_INFO {
+            pub VolumeSerialNumber: u64,
+            pub FileId: FILE_ID_128,
+        }
+
+        extern "system" {
+            pub fn GetFileInformationByHandleEx(
+                hFile: HANDLE,
+                FileInformationClass: DWORD,
+                lpFileInformation: LPVOID,
+                dwBufferSize: DWORD,
+            ) -> BOOL;
+
+            pub fn GetStdHandle(nStdHandle: DWORD) -> HANDLE;
+        }
+    }
+
+    fn open_read_attr(p: &Path) -> io::Result<RawHandle> {
         // According to MSDN, `FILE_FLAG_BACKUP_SEMANTICS`
         // must be set in order to get a handle to a directory:
         // https://msdn.microsoft.com/en-us/library/windows/desktop/aa363858(v=vs.85).aspx
         let h = unsafe {
             kernel32::CreateFileW(
-                to_utf16(p.as_ref()).as_ptr(),
+                to_utf16(p).as_ptr(),
                 0,
                 winapi::FILE_SHARE_READ
                 | winapi::FILE_SHARE_WRITE
@@ -112,7 +439,7 @@ where P: AsRef<Path>, Q: AsRef<Path> {
         if h == winapi::INVALID_HANDLE_VALUE {
             Err(io::Error::last_os_error())
         } else {
-            Ok(Handle(h))
+            Ok(RawHandle { handle: h, is_std: false })
         }
     }
 
@@ -120,9 +447,12 @@ where P: AsRef<Path>, Q: AsRef<Path> {
         s.as_os_str().encode_wide().chain(Some(0)).collect()
     }
 
-    // For correctness, it is critical that both file handles remain open while
-    // their attributes are checked for equality. In particular, the file index
-    // numbers are not guaranteed to remain stable over time.
+    // For correctness, it is critical that the file handle backing a `Key`
+    // remain open for as long as that key might be compared against
+    // another. In particular, the file index numbers are not guaranteed to
+    // remain stable over time once every handle on the file is closed,
+    // which is why `Handle` holds on to its `RawHandle` for its entire
+    // lifetime instead of closing it right after reading the info above.
     //
     // See the docs and remarks on MSDN:
     // https://msdn.microsoft.com/en-us/library/windows/desktop/aa363788(v=vs.85).aspx
@@ -133,9 +463,11 @@ where P: AsRef<Path>, Q: AsRef<Path> {
     // documented here:
     // https://msdn.microsoft.com/en-us/library/windows/desktop/hh802691(v=vs.85).aspx
     //
-    // It seems straight-forward enough to modify this code to use
-    // `FILE_ID_INFO` when available (minimum Windows Server 2012), but I don't
-    // have access to such Windows machines.
+    // `Handle::from_path` above now tries `FILE_ID_INFO` first and only
+    // falls back to the legacy `BY_HANDLE_FILE_INFORMATION` tuple when that
+    // call fails, which it will on pre-Windows-Server-2012 systems. This
+    // removes the false-positive risk below for any file system that
+    // supports it.
     //
     // Two notes.
     //
@@ -159,31 +491,67 @@ where P: AsRef<Path>, Q: AsRef<Path> {
     // detection code to report a false positive, which will prevent descending
     // into the offending directory. As far as failure modes goes, this isn't
     // that bad.
-    let h1 = try!(open_read_attr(&p1));
-    let h2 = try!(open_read_attr(&p2));
-    let i1 = try!(file_info(&h1));
-    let i2 = try!(file_info(&h2));
-
-    let k1 = (
-        i1.dwVolumeSerialNumber,
-        i1.nFileIndexHigh, i1.nFileIndexLow,
-        i1.nFileSizeHigh, i1.nFileSizeLow,
-    );
-    let k2 = (
-        i2.dwVolumeSerialNumber,
-        i2.nFileIndexHigh, i2.nFileIndexLow,
-        i2.nFileSizeHigh, i2.nFileSizeLow,
-    );
-    Ok(k1 == k2)
+}
+
+// A fallback for targets that are neither `unix` nor `windows` (e.g. wasm,
+// or anything else unknown to us), so that the crate keeps compiling
+// everywhere instead of hitting a missing `HandleImpl` type.
+//
+// There's no portable way to get at a stable file identity key here, so we
+// fall back to canonicalizing both paths and comparing the results. This is
+// considerably weaker than the unix/windows implementations: it cannot tell
+// that two hard links refer to the same file, since they canonicalize to
+// distinct paths and so compare unequal. It's offered as a conservative "probably works for the common case"
+// fallback rather than a real identity check.
+#[cfg(not(any(unix, windows)))]
+mod unknown {
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    #[derive(Debug)]
+    pub struct Handle {
+        path: PathBuf,
+    }
+
+    impl Handle {
+        pub fn from_path(p: &Path) -> io::Result<Handle> {
+            Ok(Handle { path: try!(fs::canonicalize(p)) })
+        }
+
+        pub fn stdin() -> io::Result<Handle> {
+            Err(unsupported())
+        }
+
+        pub fn stdout() -> io::Result<Handle> {
+            Err(unsupported())
+        }
+
+        pub fn stderr() -> io::Result<Handle> {
+            Err(unsupported())
+        }
+
+        pub fn key(&self) -> PathBuf {
+            self.path.clone()
+        }
+    }
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Other,
+            "identifying standard streams is not supported on this platform",
+        )
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::fs::{self, File};
 
     use tests::{tmpdir, soft_link_dir, soft_link_file};
 
-    use super::is_same_file;
+    use super::{is_same_file, AncestorHandles, Handle};
 
     // These tests are rather uninteresting. The really interesting tests
     // would stress the edge cases. On Unix, this might be comparing two files
@@ -260,4 +628,61 @@ mod tests {
         soft_link_dir(dir.join("a"), dir.join("alink")).unwrap();
         assert!(is_same_file(dir.join("a"), dir.join("alink")).unwrap());
     }
+
+    #[test]
+    fn handle_reused_across_comparisons() {
+        let tdir = tmpdir();
+        let dir = tdir.path();
+
+        File::create(dir.join("a")).unwrap();
+        File::create(dir.join("b")).unwrap();
+
+        let href = Handle::from_path(dir.join("a")).unwrap();
+        assert!(href == Handle::from_path(dir.join("a")).unwrap());
+        assert!(href != Handle::from_path(dir.join("b")).unwrap());
+    }
+
+    #[test]
+    fn handle_set_membership() {
+        let tdir = tmpdir();
+        let dir = tdir.path();
+
+        fs::create_dir(dir.join("a")).unwrap();
+        fs::create_dir(dir.join("b")).unwrap();
+
+        let mut seen = HashSet::new();
+        seen.insert(Handle::from_path(dir.join("a")).unwrap());
+
+        assert!(seen.contains(&Handle::from_path(dir.join("a")).unwrap()));
+        assert!(!seen.contains(&Handle::from_path(dir.join("b")).unwrap()));
+    }
+
+    #[test]
+    fn stdio_handles_identify_themselves() {
+        // Whatever stdout/stderr happen to be wired up to in this test
+        // process, asking for a handle to the same stream twice should
+        // always report the same identity.
+        assert!(Handle::stdout().unwrap() == Handle::stdout().unwrap());
+        assert!(Handle::stderr().unwrap() == Handle::stderr().unwrap());
+    }
+
+    #[test]
+    fn ancestor_handles_detects_symlink_loop() {
+        let tdir = tmpdir();
+        let dir = tdir.path();
+
+        fs::create_dir(dir.join("a")).unwrap();
+        soft_link_dir(dir.join("a"), dir.join("a").join("loop")).unwrap();
+
+        let mut ancestors = AncestorHandles::new();
+        ancestors.push(Handle::from_path(dir.join("a")).unwrap());
+
+        // Descending into `a/loop` resolves back to `a`, which is already
+        // on the ancestor stack.
+        let candidate = Handle::from_path(dir.join("a").join("loop")).unwrap();
+        assert!(ancestors.contains(&candidate));
+
+        ancestors.pop(&Handle::from_path(dir.join("a")).unwrap());
+        assert!(!ancestors.contains(&candidate));
+    }
 }